@@ -1,7 +1,8 @@
 use pyo3::prelude::*;
 use rayon::prelude::*;
-use std::collections::HashSet;
+use std::collections::HashMap;
 use std::env;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 /// Check if `needle` appears as a contiguous subsequence in `hay`.
 fn is_subseq_contiguous(hay: &[u32], needle: &[u32]) -> bool {
@@ -21,46 +22,563 @@ fn is_subseq_contiguous(hay: &[u32], needle: &[u32]) -> bool {
     false
 }
 
+/// Tracks how well the rare-item prefilter is doing for a single candidate, so we
+/// can abandon it in favor of a plain linear scan when it isn't paying for itself.
+/// `skipped` counts alignments a naive scan would have to check that the rare-item
+/// anchor let us skip entirely (no rare-item occurrence there at all); `attempts`
+/// counts the alignments the anchor actually visited.
+struct PrefilterState {
+    skipped: u64,
+    attempts: u64,
+}
+
+impl PrefilterState {
+    fn new() -> Self {
+        PrefilterState { skipped: 0, attempts: 0 }
+    }
+
+    /// Bail out once we've seen enough alignments and the skip ratio shows the
+    /// rare-item anchor isn't eliminating much work over a plain scan.
+    fn should_fall_back(&self) -> bool {
+        const MIN_ALIGNMENTS: u64 = 64;
+        const MIN_SKIP_RATIO: f64 = 0.2;
+        let total = self.skipped + self.attempts;
+        total >= MIN_ALIGNMENTS && (self.skipped as f64) < MIN_SKIP_RATIO * (total as f64)
+    }
+}
+
+/// Pick the globally rarest item in `cand` (per the frequency table `freq`) and
+/// return it along with its offset within the candidate. Returns `None` for an
+/// empty candidate, which has no item to anchor on.
+fn rarest_item(cand: &[u32], freq: &HashMap<u32, u32>) -> Option<(u32, usize)> {
+    if cand.is_empty() { return None; }
+    let mut best_idx = 0;
+    let mut best_item = cand[0];
+    let mut best_freq = u32::MAX;
+    for (idx, &item) in cand.iter().enumerate() {
+        let f = *freq.get(&item).unwrap_or(&0);
+        if f < best_freq {
+            best_freq = f;
+            best_item = item;
+            best_idx = idx;
+        }
+    }
+    Some((best_item, best_idx))
+}
+
+/// Match `cand` against `t` by anchoring on its rarest item: only the positions in
+/// `t` where that item occurs can possibly align a match, so every other position
+/// is rejected in O(1) instead of being compared in full. Records, in `state`, how
+/// many of the `m - n + 1` alignments a naive scan would have to check were skipped
+/// entirely (no rare-item occurrence landed on them) versus how many the anchor
+/// still had to visit, so the caller can tell whether the anchor is paying off.
+fn is_subseq_rare_anchored(
+    t: &[u32],
+    cand: &[u32],
+    rare_item: u32,
+    offset: usize,
+    state: &mut PrefilterState,
+) -> bool {
+    let n = cand.len();
+    let m = t.len();
+    if n == 0 || n > m { return false; }
+    let naive_alignments = (m - n + 1) as u64;
+    let mut visited: u64 = 0;
+    let mut found = false;
+    for (p, &it) in t.iter().enumerate() {
+        if it != rare_item { continue; }
+        if p < offset { continue; }
+        let start = p - offset;
+        if start + n > m { continue; }
+        visited += 1;
+        if &t[start..start + n] == cand {
+            found = true;
+            break;
+        }
+    }
+    state.attempts += visited;
+    state.skipped += naive_alignments.saturating_sub(visited);
+    found
+}
+
+/// Match a single candidate against a single transaction, using the rare-item prefilter
+/// (anchored on `rare`) until `*fallen_back` flips to true, after which it always falls
+/// back to a plain linear scan.
+fn match_one(
+    t: &[u32],
+    cand: &[u32],
+    rare: Option<(u32, usize)>,
+    state: &mut PrefilterState,
+    fallen_back: &mut bool,
+) -> bool {
+    match rare {
+        Some((rare_item, offset)) if !*fallen_back => {
+            let matched = is_subseq_rare_anchored(t, cand, rare_item, offset, state);
+            if state.should_fall_back() { *fallen_back = true; }
+            matched
+        }
+        _ => is_subseq_contiguous(t, cand),
+    }
+}
+
+/// Lock-free counterpart of `PrefilterState` for when several rayon chunks race to
+/// update the same candidate's accounting. `compute_supports_transaction_parallel`
+/// splits transactions across chunks small enough that any one chunk's share of the
+/// `should_fall_back` evidence is too thin to trust on its own, so every chunk adds
+/// into these shared atomics instead of keeping its own isolated `PrefilterState`,
+/// and the fallback decision is made (and shared) against the running total.
+struct SharedPrefilterState {
+    skipped: AtomicU64,
+    attempts: AtomicU64,
+    fallen_back: AtomicBool,
+}
+
+impl SharedPrefilterState {
+    fn new() -> Self {
+        SharedPrefilterState {
+            skipped: AtomicU64::new(0),
+            attempts: AtomicU64::new(0),
+            fallen_back: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Match a single candidate against a single transaction the same way `match_one` does,
+/// but folding this call's contribution into a `SharedPrefilterState` that's shared
+/// across every chunk matching this candidate, so the fallback decision reflects the
+/// whole transaction set rather than whatever sliver of it landed in one chunk.
+fn match_one_shared(
+    t: &[u32],
+    cand: &[u32],
+    rare: Option<(u32, usize)>,
+    shared: &SharedPrefilterState,
+) -> bool {
+    match rare {
+        Some((rare_item, offset)) if !shared.fallen_back.load(Ordering::Relaxed) => {
+            let mut local = PrefilterState::new();
+            let matched = is_subseq_rare_anchored(t, cand, rare_item, offset, &mut local);
+            let skipped = shared.skipped.fetch_add(local.skipped, Ordering::Relaxed) + local.skipped;
+            let attempts = shared.attempts.fetch_add(local.attempts, Ordering::Relaxed) + local.attempts;
+            if (PrefilterState { skipped, attempts }).should_fall_back() {
+                shared.fallen_back.store(true, Ordering::Relaxed);
+            }
+            matched
+        }
+        _ => is_subseq_contiguous(t, cand),
+    }
+}
+
+/// Match every candidate against every transaction independently, parallelizing over
+/// candidates and iterating transactions sequentially per candidate. Uses the rare-item
+/// prefilter (see `is_subseq_rare_anchored`) when `freq` is populated.
+///
+/// This load-balances poorly when there are few candidates and many transactions, since
+/// most cores sit idle; `compute_supports_transaction_parallel` covers that shape instead.
+fn compute_supports_per_candidate(
+    tx: &[Vec<u32>],
+    cands: &[Vec<u32>],
+    freq: &Option<HashMap<u32, u32>>,
+) -> Vec<(Vec<u32>, u32)> {
+    cands.par_iter()
+        .map(|cand| {
+            let rare = freq.as_ref().and_then(|f| rarest_item(cand, f));
+            let mut state = PrefilterState::new();
+            let mut fallen_back = false;
+            let mut freq_count: u32 = 0;
+            for t in tx.iter() {
+                if match_one(t, cand, rare, &mut state, &mut fallen_back) {
+                    freq_count += 1;
+                }
+            }
+            (cand.clone(), freq_count)
+        })
+        .collect()
+}
+
+/// Match every candidate against every transaction, parallelizing over chunks of
+/// transactions instead of over candidates. Every chunk feeds one shared per-candidate
+/// rare-item prefilter state and accumulates a local count vector; chunks are reduced
+/// element-wise at the end. Suited to the skewed shape where candidates are few but
+/// transactions are plentiful, since candidate-level parallelism would leave most cores
+/// idle in that case.
+fn compute_supports_transaction_parallel(
+    tx: &[Vec<u32>],
+    cands: &[Vec<u32>],
+    freq: &Option<HashMap<u32, u32>>,
+) -> Vec<(Vec<u32>, u32)> {
+    let num_cands = cands.len();
+    let rares: Vec<Option<(u32, usize)>> = cands.iter()
+        .map(|cand| freq.as_ref().and_then(|f| rarest_item(cand, f)))
+        .collect();
+    // Shared across every chunk below, so the fallback decision is made against the
+    // whole transaction set instead of each chunk's own small sample.
+    let shared_states: Vec<SharedPrefilterState> = (0..num_cands).map(|_| SharedPrefilterState::new()).collect();
+
+    let chunk_count = (rayon::current_num_threads() * 4).max(1);
+    let chunk_size = std::cmp::max(1, tx.len() / chunk_count);
+
+    let counts = tx.par_chunks(chunk_size)
+        .map(|chunk| {
+            let mut local_counts = vec![0u32; num_cands];
+            for t in chunk {
+                for (i, cand) in cands.iter().enumerate() {
+                    if match_one_shared(t, cand, rares[i], &shared_states[i]) {
+                        local_counts[i] += 1;
+                    }
+                }
+            }
+            local_counts
+        })
+        .reduce(
+            || vec![0u32; num_cands],
+            |mut a, b| {
+                for i in 0..num_cands { a[i] += b[i]; }
+                a
+            },
+        );
+
+    cands.iter().cloned().zip(counts).collect()
+}
+
+/// Switch to transaction-chunk parallelism when there aren't enough candidates to keep
+/// every thread busy under candidate-level parallelism, and there are enough
+/// transactions for chunking to actually pay for its extra per-chunk bookkeeping.
+fn should_use_transaction_parallel(num_candidates: usize, num_transactions: usize, forced: Option<bool>) -> bool {
+    if let Some(forced) = forced { return forced; }
+    const MIN_TRANSACTIONS_PER_THREAD: usize = 16;
+    let threads = rayon::current_num_threads();
+    num_candidates < threads && num_transactions >= threads * MIN_TRANSACTIONS_PER_THREAD
+}
+
+// Rolling-hash parameters for the batched matcher. The modulus is the Mersenne prime
+// 2^61 - 1, chosen so products of two reduced values fit comfortably in a u128.
+const RK_BASE: u64 = 1_000_003;
+const RK_MOD: u64 = (1u64 << 61) - 1;
+
+fn rk_mulmod(a: u64, b: u64, modulus: u64) -> u64 {
+    ((a as u128 * b as u128) % modulus as u128) as u64
+}
+
+fn rk_pow(base: u64, mut exp: usize, modulus: u64) -> u64 {
+    let mut result: u64 = 1;
+    let mut b = base % modulus;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            result = rk_mulmod(result, b, modulus);
+        }
+        b = rk_mulmod(b, b, modulus);
+        exp >>= 1;
+    }
+    result
+}
+
+/// Polynomial rolling hash of `items`: sum(item[i] * base^(len-1-i)) mod modulus.
+fn rk_hash(items: &[u32], base: u64, modulus: u64) -> u64 {
+    let mut h: u64 = 0;
+    for &item in items {
+        h = (rk_mulmod(h, base, modulus) + item as u64 % modulus) % modulus;
+    }
+    h
+}
+
+/// All candidates sharing a single length `len`, bucketed by rolling hash so a
+/// transaction window can be matched against many candidates with one hash lookup.
+struct LengthGroup {
+    len: usize,
+    /// base^(len-1) mod modulus, needed to remove the leading item when rolling.
+    lead_factor: u64,
+    /// hash -> indices into the shared `cands` slice of candidates with that hash.
+    buckets: HashMap<u64, Vec<usize>>,
+}
+
+fn group_candidates_by_length(cands: &[Vec<u32>]) -> Vec<LengthGroup> {
+    let mut by_len: HashMap<usize, Vec<usize>> = HashMap::new();
+    for (idx, cand) in cands.iter().enumerate() {
+        if cand.is_empty() { continue; }
+        by_len.entry(cand.len()).or_default().push(idx);
+    }
+    by_len.into_iter()
+        .map(|(len, idxs)| {
+            let mut buckets: HashMap<u64, Vec<usize>> = HashMap::new();
+            for idx in idxs {
+                let h = rk_hash(&cands[idx], RK_BASE, RK_MOD);
+                buckets.entry(h).or_default().push(idx);
+            }
+            LengthGroup { len, lead_factor: rk_pow(RK_BASE, len - 1, RK_MOD), buckets }
+        })
+        .collect()
+}
+
+/// Slide a rolling hash of each group's length across `t`, marking every candidate
+/// found at least once in `matched`. A hash hit is verified against the real candidate
+/// to defeat collisions, and a candidate is short-circuited once it is found.
+fn match_transaction_batched(t: &[u32], groups: &[LengthGroup], cands: &[Vec<u32>], matched: &mut [bool]) {
+    let m = t.len();
+    for group in groups {
+        let l = group.len;
+        if l > m { continue; }
+        let mut h = rk_hash(&t[0..l], RK_BASE, RK_MOD);
+        let mut pos = 0usize;
+        loop {
+            if let Some(idxs) = group.buckets.get(&h) {
+                for &idx in idxs {
+                    if !matched[idx] && t[pos..pos + l] == cands[idx][..] {
+                        matched[idx] = true;
+                    }
+                }
+            }
+            if pos + l >= m { break; }
+            let leading = t[pos] as u64 % RK_MOD;
+            let trailing = t[pos + l] as u64 % RK_MOD;
+            let lead_term = rk_mulmod(leading, group.lead_factor, RK_MOD);
+            let mut hh = if h >= lead_term { h - lead_term } else { RK_MOD - (lead_term - h) };
+            hh = rk_mulmod(hh, RK_BASE, RK_MOD);
+            h = (hh + trailing) % RK_MOD;
+            pos += 1;
+        }
+    }
+}
+
+/// Batched mode: invert the candidate/transaction loop so each transaction is scanned
+/// once for all candidates of a given length, rather than once per candidate. Candidates
+/// are grouped by length and probed via a rolling hash; workers accumulate per-candidate
+/// counts locally (rayon fold) and are reduced element-wise at the end.
+fn compute_supports_batched(tx: &[Vec<u32>], cands: &[Vec<u32>]) -> Vec<(Vec<u32>, u32)> {
+    let num_cands = cands.len();
+    let groups = group_candidates_by_length(cands);
+
+    let counts = tx.par_iter()
+        .fold(
+            || (vec![0u32; num_cands], vec![false; num_cands]),
+            |(mut counts, mut matched), t| {
+                for m in matched.iter_mut() { *m = false; }
+                match_transaction_batched(t, &groups, cands, &mut matched);
+                for (i, &was_matched) in matched.iter().enumerate() {
+                    if was_matched { counts[i] += 1; }
+                }
+                (counts, matched)
+            },
+        )
+        .map(|(counts, _)| counts)
+        .reduce(
+            || vec![0u32; num_cands],
+            |mut a, b| {
+                for i in 0..num_cands { a[i] += b[i]; }
+                a
+            },
+        );
+
+    cands.iter().cloned().zip(counts).collect()
+}
+
+/// Candidates are worth batching once there are enough of them that grouping by length
+/// actually saves repeated transaction scans; below that, per-candidate overhead is lower.
+fn should_use_batch(cands: &[Vec<u32>], forced: Option<bool>) -> bool {
+    if let Some(forced) = forced { return forced; }
+    const BATCH_THRESHOLD: usize = 32;
+    cands.len() >= BATCH_THRESHOLD
+}
+
 /// Compute supports for candidate patterns across transactions.
 ///
-/// This uses contiguous subsequence matching. The computation parallelizes over
-/// candidates, and iterates transactions sequentially to avoid nested Rayon overhead.
+/// This uses contiguous subsequence matching. When there are many candidates, a batched
+/// mode instead groups candidates by length and scans each transaction once with a
+/// rolling hash (see `compute_supports_batched`), trading memory for a large asymptotic
+/// win; pass `use_batch` to force either mode for benchmarking.
+///
+/// Otherwise the computation parallelizes over candidates, iterating transactions
+/// sequentially per candidate to avoid nested Rayon overhead. That load-balances poorly
+/// when there are few candidates and many transactions, since most cores sit idle, so in
+/// that shape it instead parallelizes over transaction chunks (see
+/// `compute_supports_transaction_parallel`); pass `force_transaction_parallel` to force
+/// either mode for benchmarking.
+///
+/// An optional rare-item prefilter can be enabled via the `GSPPY_PREFILTER` env var
+/// ("1"/"true"): each candidate is anchored on its globally rarest item, so matching
+/// only has to inspect transaction positions where that item occurs. Per candidate we
+/// track how often the anchor actually let us skip an alignment versus how many full
+/// comparisons it still required, and fall back to a plain linear scan once the ratio
+/// shows the prefilter isn't pulling its weight. The prefilter only applies to the
+/// non-batched paths.
 #[pyfunction]
-#[pyo3(text_signature = "(transactions, candidates, min_support, /)")]
-fn compute_supports_py(py: Python<'_>, transactions: Bound<PyAny>, candidates: Bound<PyAny>, min_support: u32) -> PyResult<Vec<(Vec<u32>, u32)>> {
+#[pyo3(signature = (transactions, candidates, min_support, use_batch=None, force_transaction_parallel=None))]
+#[pyo3(text_signature = "(transactions, candidates, min_support, use_batch=None, force_transaction_parallel=None, /)")]
+fn compute_supports_py(
+    py: Python<'_>,
+    transactions: Bound<PyAny>,
+    candidates: Bound<PyAny>,
+    min_support: u32,
+    use_batch: Option<bool>,
+    force_transaction_parallel: Option<bool>,
+) -> PyResult<Vec<(Vec<u32>, u32)>> {
     // Convert Python lists -> Vec<Vec<u32>>
     let tx: Vec<Vec<u32>> = transactions.extract()?;
     let cands: Vec<Vec<u32>> = candidates.extract()?;
 
-    // Optional presence prefilter controlled by env var GSPPY_PREFILTER ("1"/"true")
+    // Optional rare-item prefilter controlled by env var GSPPY_PREFILTER ("1"/"true")
     let prefilter = env::var("GSPPY_PREFILTER")
         .ok()
         .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
         .unwrap_or(false);
 
-    // Precompute per-transaction item sets if prefilter is enabled
-    let tx_sets: Option<Vec<HashSet<u32>>> = if prefilter {
-        Some(tx.iter().map(|t| t.iter().copied().collect::<HashSet<u32>>()).collect())
+    // Precompute a global item frequency table if the prefilter is enabled
+    let freq: Option<HashMap<u32, u32>> = if prefilter {
+        let mut map: HashMap<u32, u32> = HashMap::new();
+        for t in &tx {
+            for &item in t {
+                *map.entry(item).or_insert(0) += 1;
+            }
+        }
+        Some(map)
     } else { None };
 
     let out = py.allow_threads(|| {
-        // Parallelize over candidates; iterate transactions sequentially per candidate
-        let out: Vec<(Vec<u32>, u32)> = cands.par_iter()
+        if should_use_batch(&cands, use_batch) {
+            compute_supports_batched(&tx, &cands)
+        } else if should_use_transaction_parallel(cands.len(), tx.len(), force_transaction_parallel) {
+            compute_supports_transaction_parallel(&tx, &cands, &freq)
+        } else {
+            compute_supports_per_candidate(&tx, &cands, &freq)
+        }
+    });
+
+    Ok(out.into_iter().filter(|(_, f)| *f >= min_support).collect())
+}
+
+/// Time constraints for true GSP matching: an element of the pattern matches a
+/// transaction event whose timestamp lies strictly after `min_gap` and at most
+/// `max_gap` past the previously matched element, adjacent events within
+/// `window_size` are collapsed into a single matched element, and the overall
+/// match must span no more than `max_span` from its first to its last element.
+struct GspConstraints {
+    min_gap: i64,
+    max_gap: i64,
+    window_size: i64,
+    max_span: i64,
+}
+
+/// The two timestamps `match_gsp_from` needs to carry through its recursion: the end of
+/// the previously matched element (for the gap check) and the start of the very first
+/// matched element (for the `max_span` check, fixed for the whole search).
+struct MatchCursor {
+    prev_end_time: i64,
+    first_time: i64,
+}
+
+/// Try to match `pattern[pi..]` against `events[start_idx..]` under `c`, given the
+/// timestamp of the previously matched element (`prev_end_time`) and of the first
+/// matched element (`first_time`, for the `max_span` check, fixed for the whole call).
+/// Backtracks over the choice of transaction event for each pattern element: a greedy
+/// earliest match can fail further on where a later occurrence of the same item would
+/// succeed, so on failure we keep trying later occurrences rather than giving up
+/// immediately. `(pi, start_idx, prev_end_time)` is memoized: with `first_time` (and so
+/// the `max_span` deadline) fixed for the call, that triple fully determines whether the
+/// rest of the pattern can match, and repeated items within the gap/window bounds would
+/// otherwise revisit the same state exponentially often.
+fn match_gsp_from(
+    events: &[(i64, u32)],
+    pattern: &[u32],
+    pi: usize,
+    start_idx: usize,
+    cursor: MatchCursor,
+    c: &GspConstraints,
+    memo: &mut HashMap<(usize, usize, i64), bool>,
+) -> bool {
+    if pi == pattern.len() { return true; }
+    let key = (pi, start_idx, cursor.prev_end_time);
+    if let Some(&cached) = memo.get(&key) { return cached; }
+
+    let item = pattern[pi];
+    let mut matched = false;
+    for idx in start_idx..events.len() {
+        let (ts, it) = events[idx];
+        if it != item { continue; }
+
+        let gap = ts - cursor.prev_end_time;
+        if gap <= c.min_gap || gap > c.max_gap { continue; }
+        if ts - cursor.first_time > c.max_span { continue; }
+
+        // Events within window_size of this match collapse into the same element,
+        // so the next pattern element must be matched after the whole window.
+        let window_end = ts + c.window_size;
+        let mut next_idx = idx + 1;
+        while next_idx < events.len() && events[next_idx].0 <= window_end {
+            next_idx += 1;
+        }
+
+        let next_cursor = MatchCursor { prev_end_time: ts, first_time: cursor.first_time };
+        if match_gsp_from(events, pattern, pi + 1, next_idx, next_cursor, c, memo) {
+            matched = true;
+            break;
+        }
+    }
+
+    memo.insert(key, matched);
+    matched
+}
+
+/// Whether `pattern` occurs as a gapped subsequence of `events` under the GSP time
+/// constraints `c`. Tries every occurrence of the first pattern element as the match's
+/// starting point (each fixes a different `max_span` deadline), memoizing the
+/// backtracking search for that starting point in `match_gsp_from`.
+fn contains_gsp_pattern(events: &[(i64, u32)], pattern: &[u32], c: &GspConstraints) -> bool {
+    if pattern.is_empty() { return false; }
+    let first_item = pattern[0];
+    for start_idx in 0..events.len() {
+        let (ts0, it0) = events[start_idx];
+        if it0 != first_item { continue; }
+
+        if pattern.len() == 1 { return true; }
+
+        let window_end = ts0 + c.window_size;
+        let mut next_idx = start_idx + 1;
+        while next_idx < events.len() && events[next_idx].0 <= window_end {
+            next_idx += 1;
+        }
+
+        let cursor = MatchCursor { prev_end_time: ts0, first_time: ts0 };
+        let mut memo: HashMap<(usize, usize, i64), bool> = HashMap::new();
+        if match_gsp_from(events, pattern, 1, next_idx, cursor, c, &mut memo) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Compute supports for candidate patterns using true GSP matching.
+///
+/// Unlike `compute_supports_py`, which only finds contiguous windows, this matches a
+/// pattern as a gapped subsequence of timestamped events: `constraints` is a
+/// `(min_gap, max_gap, window_size, max_span)` tuple, where `min_gap`/`max_gap` bound the
+/// time distance allowed between consecutive matched elements, `window_size` lets events
+/// close together in time count as one matched element, and `max_span` bounds the total
+/// time from the first to the last matched element. Each transaction is a sequence of
+/// `(timestamp, item)` events ordered by timestamp. Parallelizes over candidates like
+/// the per-candidate path of `compute_supports_py`.
+#[pyfunction]
+#[pyo3(text_signature = "(transactions, candidates, min_support, constraints, /)")]
+fn compute_supports_gsp_py(
+    py: Python<'_>,
+    transactions: Bound<PyAny>,
+    candidates: Bound<PyAny>,
+    min_support: u32,
+    constraints: (i64, i64, i64, i64),
+) -> PyResult<Vec<(Vec<u32>, u32)>> {
+    let tx: Vec<Vec<(i64, u32)>> = transactions.extract()?;
+    let cands: Vec<Vec<u32>> = candidates.extract()?;
+    let (min_gap, max_gap, window_size, max_span) = constraints;
+    let constraints = GspConstraints { min_gap, max_gap, window_size, max_span };
+
+    let out = py.allow_threads(|| {
+        cands.par_iter()
             .map(|cand| {
-                let mut freq: u32 = 0;
-                for (idx, t) in tx.iter().enumerate() {
-                    if let Some(ref sets) = tx_sets {
-                        // Quick reject if any item in candidate is not present in this transaction
-                        let set = &sets[idx];
-                        if !cand.iter().all(|it| set.contains(it)) { continue; }
-                    }
-                    if is_subseq_contiguous(t, cand) { freq += 1; }
-                }
-                (cand.clone(), freq)
+                let support = tx.iter()
+                    .filter(|events| contains_gsp_pattern(events, cand, &constraints))
+                    .count() as u32;
+                (cand.clone(), support)
             })
-            .filter(|(_, f)| *f >= min_support)
-            .collect();
-        out
+            .filter(|(_, support)| *support >= min_support)
+            .collect()
     });
 
     Ok(out)
@@ -69,5 +587,187 @@ fn compute_supports_py(py: Python<'_>, transactions: Bound<PyAny>, candidates: B
 #[pymodule]
 fn _gsppy_rust(_py: Python, m: &Bound<PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(compute_supports_py, m)?)?;
+    m.add_function(wrap_pyfunction!(compute_supports_gsp_py, m)?)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn naive_support(tx: &[Vec<u32>], cand: &[u32]) -> u32 {
+        tx.iter().filter(|t| is_subseq_contiguous(t, cand)).count() as u32
+    }
+
+    #[test]
+    fn rarest_item_returns_none_for_empty_candidate() {
+        let freq: HashMap<u32, u32> = HashMap::new();
+        assert_eq!(rarest_item(&[], &freq), None);
+    }
+
+    #[test]
+    fn rare_item_prefilter_matches_naive_scan() {
+        let tx: Vec<Vec<u32>> = vec![
+            vec![1, 2, 3, 4, 5],
+            vec![2, 3, 1, 2, 3, 4],
+            vec![5, 5, 5, 1, 2, 3],
+            vec![9, 9, 9],
+            vec![],
+        ];
+        let cands: Vec<Vec<u32>> = vec![
+            vec![1, 2, 3],
+            vec![2, 3, 4],
+            vec![1, 2, 3, 4, 5],
+            vec![7, 8],
+            vec![],
+        ];
+
+        let mut freq: HashMap<u32, u32> = HashMap::new();
+        for t in &tx {
+            for &item in t {
+                *freq.entry(item).or_insert(0) += 1;
+            }
+        }
+        let freq = Some(freq);
+
+        for (cand, support) in compute_supports_per_candidate(&tx, &cands, &freq) {
+            assert_eq!(support, naive_support(&tx, &cand), "mismatch for {:?}", cand);
+        }
+    }
+
+    #[test]
+    fn rare_item_prefilter_does_not_fall_back_in_favorable_shape() {
+        // The shape this prefilter targets: a rare candidate item anchoring a match in
+        // large transactions, where the anchor only ever visits a handful of alignments
+        // out of thousands a naive scan would check. It should keep paying off and never
+        // trip the fallback.
+        let cand = vec![0u32, 1, 2];
+        let mut t = vec![9u32; 2000];
+        t[0] = 0;
+        t[1] = 1;
+        t[2] = 2;
+
+        let mut state = PrefilterState::new();
+        for _ in 0..10 {
+            assert!(is_subseq_rare_anchored(&t, &cand, 0, 0, &mut state));
+            assert!(
+                !state.should_fall_back(),
+                "prefilter should not fall back when it is saving almost all of the work"
+            );
+        }
+    }
+
+    #[test]
+    fn batched_matcher_matches_naive_scan() {
+        let tx: Vec<Vec<u32>> = vec![
+            vec![1, 2, 3, 4, 5, 6],
+            vec![4, 5, 6, 1, 2, 3],
+            vec![7, 8, 9],
+            vec![1, 2, 3, 1, 2, 3],
+            vec![],
+        ];
+        // Several candidates share a length so they land in the same LengthGroup bucket;
+        // include near-collisions (same length, different items) to exercise the
+        // hash-hit verification step.
+        let cands: Vec<Vec<u32>> = vec![
+            vec![1, 2, 3],
+            vec![4, 5, 6],
+            vec![2, 3, 4],
+            vec![9, 9],
+            vec![1, 2, 3, 4, 5, 6],
+            vec![],
+        ];
+
+        for (cand, support) in compute_supports_batched(&tx, &cands) {
+            assert_eq!(support, naive_support(&tx, &cand), "mismatch for {:?}", cand);
+        }
+    }
+
+    #[test]
+    fn gsp_respects_gap_window_and_span_constraints() {
+        let events = vec![(0i64, 1u32), (1, 2), (2, 3), (50, 1), (51, 9), (52, 3)];
+        let c = GspConstraints { min_gap: 0, max_gap: 2, window_size: 0, max_span: 1000 };
+        // The first `1` at t=0 can't reach a `9` within max_gap, but backtracking finds
+        // the later `1` at t=50 that can.
+        assert!(contains_gsp_pattern(&events, &[1, 9, 3], &c));
+        // No `9` follows `2` closely enough anywhere in the transaction.
+        assert!(!contains_gsp_pattern(&events, &[2, 9], &c));
+
+        let far_apart = vec![(0i64, 1u32), (100, 2)];
+        let tight_span = GspConstraints { min_gap: 0, max_gap: 1000, window_size: 0, max_span: 50 };
+        assert!(!contains_gsp_pattern(&far_apart, &[1, 2], &tight_span));
+
+        // Events within window_size of the first match collapse into one element, so the
+        // second pattern element must be searched for only after the whole window.
+        let windowed = vec![(0i64, 1u32), (1, 5), (2, 2), (10, 2)];
+        let c_window = GspConstraints { min_gap: 0, max_gap: 1000, window_size: 2, max_span: 1000 };
+        assert!(contains_gsp_pattern(&windowed, &[1, 2], &c_window));
+    }
+
+    #[test]
+    fn gsp_backtracking_on_repeated_items_does_not_blow_up() {
+        // Regression test: without memoizing (pi, start_idx, prev_end_time), this search
+        // is exponential in the number of same-item events (C(n, k)-shaped) because the
+        // trailing item never occurs and every alignment of the repeated prefix is tried.
+        let events: Vec<(i64, u32)> = (0..60).map(|i| (i as i64, 1)).collect();
+        let mut pattern = vec![1u32; 30];
+        pattern.push(99);
+        let c = GspConstraints { min_gap: 0, max_gap: 1000, window_size: 0, max_span: 10_000 };
+        assert!(!contains_gsp_pattern(&events, &pattern, &c));
+    }
+
+    #[test]
+    fn transaction_parallel_strategy_considers_both_sizes() {
+        let threads = rayon::current_num_threads();
+        // No candidates is always "few" relative to any thread count, but with too few
+        // transactions chunking still wouldn't pay for itself.
+        assert!(!should_use_transaction_parallel(0, 4, None));
+        // Same candidate count, but now plenty of transactions: the skewed shape this
+        // strategy targets.
+        assert!(should_use_transaction_parallel(0, threads * 32, None));
+        // Plenty of candidates: candidate-level parallelism already saturates cores.
+        assert!(!should_use_transaction_parallel(threads + 10, threads * 32, None));
+        // An explicit override always wins regardless of input shape.
+        assert!(should_use_transaction_parallel(threads + 10, 1, Some(true)));
+        assert!(!should_use_transaction_parallel(0, threads * 32, Some(false)));
+    }
+
+    #[test]
+    fn transaction_parallel_matches_naive_scan() {
+        let tx: Vec<Vec<u32>> = vec![
+            vec![1, 2, 3],
+            vec![2, 3, 4, 1, 2, 3],
+            vec![5, 6, 7],
+            vec![],
+        ];
+        let cands: Vec<Vec<u32>> = vec![vec![1, 2, 3], vec![2, 3, 4], vec![9, 9], vec![]];
+
+        for (cand, support) in compute_supports_transaction_parallel(&tx, &cands, &None) {
+            assert_eq!(support, naive_support(&tx, &cand), "mismatch for {:?}", cand);
+        }
+    }
+
+    #[test]
+    fn shared_prefilter_state_falls_back_on_accumulated_evidence() {
+        // A poor anchor: the "rare" item actually occurs in every single position, so
+        // each transaction contributes only a sliver of skipped/attempts evidence —
+        // far short of `MIN_ALIGNMENTS` on its own. Simulate several chunks each
+        // calling `match_one_shared` with just one transaction, as
+        // `compute_supports_transaction_parallel` would with small chunks, and check
+        // the shared state still accumulates across them and eventually falls back,
+        // which a chunk-local `PrefilterState` reset on every call never could.
+        let cand = vec![0u32, 1, 2];
+        let t = vec![0u32, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let shared = SharedPrefilterState::new();
+
+        for _ in 0..100 {
+            match_one_shared(&t, &cand, Some((0, 0)), &shared);
+        }
+
+        assert!(
+            shared.fallen_back.load(Ordering::Relaxed),
+            "fallback should trigger once accumulated evidence crosses the threshold, \
+             even though no single call's contribution would reach it alone"
+        );
+    }
+}